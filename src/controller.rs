@@ -1,17 +1,23 @@
 use anyhow::Result;
 use futures::prelude::*;
-use std::collections::HashMap;
+use handlebars::Handlebars;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use k8s_openapi::api::core::v1::Secret;
 use k8s_openapi::ByteString;
 use kube::api::{Api, ListParams};
 use kube::Client;
 use kube_runtime::controller::{Context, Controller, ReconcilerAction};
+use kube_runtime::finalizer::{finalizer, Error as FinalizerError, Event as FinalizerEvent};
+use kube_runtime::reflector::ObjectRef;
 use structopt::StructOpt;
 use thiserror::Error;
 
-use crate::crd::{check_crd_status, PatchStrategy, TradeSecret};
+use crate::crd::{check_crd_status, PatchStrategy, PatchTemplateItem, TradeSecret};
 use crate::duration::Duration;
+use crate::metrics::{Metrics, Readiness};
 
 /// The error type returned by the controller.
 #[derive(Error, Debug)]
@@ -24,22 +30,175 @@ pub enum ReconcilerError {
     #[error("Source data field is missing")]
     SourceFieldMissing,
 
+    /// A template item failed to render, e.g. because it referenced a
+    /// source key that doesn't exist.
+    #[error("Failed to render template for '{0}': {1}")]
+    TemplateRenderFailed(String, handlebars::RenderError),
+
+    /// The finalizer helper itself failed to add or remove the finalizer.
+    #[error("Finalizer handling failed: {0}")]
+    FinalizerFailed(String),
+
+    /// A TradeSecret asked to read from or write to another namespace, but
+    /// the controller wasn't started with `--allow-cross-namespace`.
+    #[error("Cross-namespace secrets are disabled; pass --allow-cross-namespace to enable them")]
+    CrossNamespaceDisabled,
+
     /// Random errors
     #[error("Unknown error")]
     Unknown,
 }
 
+impl ReconcilerError {
+    /// A short, stable label identifying the variant, for use as a metric label.
+    fn metric_label(&self) -> &'static str {
+        match self {
+            ReconcilerError::SecretNotFound => "secret_not_found",
+            ReconcilerError::SourceFieldMissing => "source_field_missing",
+            ReconcilerError::TemplateRenderFailed(_, _) => "template_render_failed",
+            ReconcilerError::FinalizerFailed(_) => "finalizer_failed",
+            ReconcilerError::CrossNamespaceDisabled => "cross_namespace_disabled",
+            ReconcilerError::Unknown => "unknown",
+        }
+    }
+}
+
+/// The finalizer added to a TradeSecret so its Cleanup event fires before
+/// the object is actually deleted.
+const TRADE_SECRET_FINALIZER: &str = "secrets.ohnozombi.es/cleanup";
+
+/// The annotation, per TradeSecret, recording which destination keys it
+/// owns. Keyed on the TradeSecret's name so multiple TradeSecrets can write
+/// into the same destination secret without clobbering each other's records.
+fn managed_by_annotation(trade_name: &str) -> String {
+    format!("secrets.ohnozombi.es/managed-by-{}", trade_name)
+}
+
+/// Parses a `managed-by-<name>` annotation value back into the destination
+/// keys it records. `None` and an empty string both mean "nothing managed".
+fn parse_managed_keys(raw: Option<&String>) -> Vec<String> {
+    raw.map(|keys| {
+        keys.split(',')
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Maps a namespaced Secret name to the TradeSecrets that reference it as
+/// their source or destination, so a Secret watch event can be turned into
+/// the TradeSecrets that need to be requeued.
+type SecretIndex = Arc<Mutex<HashMap<(String, String), HashSet<ObjectRef<TradeSecret>>>>>;
+
+/// The namespace a secret reference resolves to: the TradeSecret's own
+/// namespace unless an explicit `sourceNamespace`/`destinationNamespace`
+/// was given.
+fn effective_namespace(trade_namespace: &str, explicit: &Option<String>) -> String {
+    explicit.clone().unwrap_or_else(|| trade_namespace.to_owned())
+}
+
+/// Drops `trade_ref` from any `index` entry not in `keep`, removing the
+/// entry entirely once its ref set is empty.
+fn prune_stale_refs(
+    index: &mut HashMap<(String, String), HashSet<ObjectRef<TradeSecret>>>,
+    trade_ref: &ObjectRef<TradeSecret>,
+    keep: &[(String, String)],
+) {
+    index.retain(|key, trade_refs| {
+        if !keep.contains(key) {
+            trade_refs.remove(trade_ref);
+        }
+        !trade_refs.is_empty()
+    });
+}
+
+/// Drops `trade_ref` from every `index` entry, removing the entry entirely
+/// once its ref set is empty.
+fn remove_all_refs(
+    index: &mut HashMap<(String, String), HashSet<ObjectRef<TradeSecret>>>,
+    trade_ref: &ObjectRef<TradeSecret>,
+) {
+    index.retain(|_, trade_refs| {
+        trade_refs.remove(trade_ref);
+        !trade_refs.is_empty()
+    });
+}
+
+/// Decodes a secret's data into a UTF-8 Handlebars template context. A key
+/// that isn't valid UTF-8 is left out of the context rather than failing the
+/// whole reconcile, since most TradeSecrets only reference a handful of
+/// keys; strict-mode rendering still fails if a template actually references
+/// the omitted key.
+fn decode_template_context(
+    data: Option<&std::collections::BTreeMap<String, ByteString>>,
+) -> HashMap<String, String> {
+    data.map(|data| {
+        data.iter()
+            .filter_map(|(key, value)| match std::str::from_utf8(&value.0) {
+                Ok(s) => Some((key.clone(), s.to_owned())),
+                Err(_) => {
+                    eprintln!(
+                        "Source secret key '{}' is not valid UTF-8; omitting from template context",
+                        key
+                    );
+                    None
+                }
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Renders each `PatchTemplateItem` against `source_context` (exposed to the
+/// template as `source`), strict-mode so a template referencing a missing
+/// key fails the reconcile instead of rendering an empty string.
+fn render_template_items(
+    items: &[PatchTemplateItem],
+    source_context: &HashMap<String, String>,
+) -> Result<HashMap<String, ByteString>, ReconcilerError> {
+    let mut registry = Handlebars::new();
+    // Secrets aren't HTML; rendering must not escape their contents
+    registry.register_escape_fn(handlebars::no_escape);
+    registry.set_strict_mode(true);
+
+    let context = serde_json::json!({ "source": source_context });
+
+    items
+        .iter()
+        .map(|item| {
+            let rendered = registry
+                .render_template(&item.template, &context)
+                .map_err(|e| ReconcilerError::TemplateRenderFailed(item.destination.clone(), e))?;
+
+            Ok((item.destination.clone(), ByteString(rendered.into_bytes())))
+        })
+        .collect()
+}
+
 /// The context passed to the controller
 struct Ctx {
     client: kube::Client,
     requeue_time: Duration,
+    secret_index: SecretIndex,
+    metrics: Metrics,
+    allow_cross_namespace: bool,
 }
 
 impl Ctx {
-    fn new(client: kube::Client, requeue_time: Duration) -> Self {
+    fn new(
+        client: kube::Client,
+        requeue_time: Duration,
+        secret_index: SecretIndex,
+        metrics: Metrics,
+        allow_cross_namespace: bool,
+    ) -> Self {
         Self {
             client,
             requeue_time,
+            secret_index,
+            metrics,
+            allow_cross_namespace,
         }
     }
 
@@ -47,11 +206,102 @@ impl Ctx {
         &self.client
     }
 
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Resolves a secret reference's namespace, rejecting cross-namespace
+    /// references unless the controller was started with
+    /// `--allow-cross-namespace`.
+    fn resolve_namespace(
+        &self,
+        trade_namespace: &str,
+        explicit: &Option<String>,
+    ) -> Result<String, ReconcilerError> {
+        let namespace = effective_namespace(trade_namespace, explicit);
+
+        if namespace != trade_namespace && !self.allow_cross_namespace {
+            return Err(ReconcilerError::CrossNamespaceDisabled);
+        }
+
+        Ok(namespace)
+    }
+
     fn requeue_action(&self) -> ReconcilerAction {
         ReconcilerAction {
             requeue_after: Some(self.requeue_time.into()),
         }
     }
+
+    /// Records that `trade` reads from/writes to its source and destination
+    /// Secrets, so a change to either is mapped back to it. A ref that would
+    /// require `--allow-cross-namespace` isn't indexed when the flag is off,
+    /// same as `apply`/`cleanup` already refuse to touch it, so the
+    /// cluster-wide Secret watch is never actually triggered by a namespace
+    /// the controller isn't allowed to read from or write to. Also prunes
+    /// any mapping left over from a previous reconcile whose source/
+    /// destination no longer applies (e.g. `source`/`destination`/
+    /// `*Namespace` changed), and refreshes the watched-TradeSecrets gauge.
+    fn index_trade_secret(&self, trade: &TradeSecret) {
+        let namespace = trade
+            .metadata
+            .namespace
+            .clone()
+            .expect("TradeSecrets should always be namespaced");
+        let trade_ref = ObjectRef::from_obj(trade);
+
+        let mut index = self.secret_index.lock().expect("secret index lock poisoned");
+
+        let refs: Vec<(String, String)> = [
+            (
+                effective_namespace(&namespace, &trade.spec.source_namespace),
+                trade.spec.source.clone(),
+            ),
+            (
+                effective_namespace(&namespace, &trade.spec.destination_namespace),
+                trade.spec.destination.clone(),
+            ),
+        ]
+        .into_iter()
+        .filter(|(secret_namespace, _)| {
+            secret_namespace == &namespace || self.allow_cross_namespace
+        })
+        .collect();
+
+        // Drop this TradeSecret from any secret it's indexed under that isn't
+        // one of its current refs, so a changed source/destination doesn't
+        // leave a stale mapping behind.
+        prune_stale_refs(&mut index, &trade_ref, &refs);
+
+        for key in refs {
+            index
+                .entry(key)
+                .or_insert_with(HashSet::new)
+                .insert(trade_ref.clone());
+        }
+
+        self.refresh_watched_gauge(&index);
+    }
+
+    /// Removes every mapping pointing at `trade` from the secret index. Called
+    /// when a TradeSecret is deleted, so the index doesn't grow without bound.
+    fn deindex_trade_secret(&self, trade: &TradeSecret) {
+        let trade_ref = ObjectRef::from_obj(trade);
+
+        let mut index = self.secret_index.lock().expect("secret index lock poisoned");
+
+        remove_all_refs(&mut index, &trade_ref);
+
+        self.refresh_watched_gauge(&index);
+    }
+
+    fn refresh_watched_gauge(
+        &self,
+        index: &HashMap<(String, String), HashSet<ObjectRef<TradeSecret>>>,
+    ) {
+        let watched: HashSet<_> = index.values().flatten().collect();
+        self.metrics.watched_trade_secrets.set(watched.len() as i64);
+    }
 }
 
 /// The core of the controller.
@@ -59,10 +309,7 @@ impl Ctx {
 /// Takes a trade secret
 /// Performs updates, if necessary.
 /// Requeues the trade secret for processing again in 5 minutes
-async fn reconcile(
-    trade: TradeSecret,
-    ctx: Context<Ctx>,
-) -> Result<ReconcilerAction, ReconcilerError> {
+async fn apply(trade: TradeSecret, ctx: Context<Ctx>) -> Result<ReconcilerAction, ReconcilerError> {
     // eprintln!("TradeSecret: {:?}", trade);
 
     // The namespace the TradeSecret is in is the namespace the secrets are in
@@ -72,118 +319,341 @@ async fn reconcile(
         .as_ref()
         .expect("TradeSecrets should always be namespaced");
 
-    eprintln!("Updating {}.{}", namespace, trade.metadata.name.unwrap());
+    let trade_name = trade
+        .metadata
+        .name
+        .clone()
+        .expect("TradeSecrets should always be named");
 
-    let secrets: Api<Secret> = Api::namespaced(ctx.get_ref().client().clone(), namespace);
+    eprintln!("Updating {}.{}", namespace, trade_name);
 
-    let source_secret = secrets
+    let source_namespace = ctx
+        .get_ref()
+        .resolve_namespace(namespace, &trade.spec.source_namespace)?;
+    let destination_namespace = ctx
+        .get_ref()
+        .resolve_namespace(namespace, &trade.spec.destination_namespace)?;
+
+    let source_secrets: Api<Secret> = Api::namespaced(ctx.get_ref().client().clone(), &source_namespace);
+    let dest_secrets: Api<Secret> =
+        Api::namespaced(ctx.get_ref().client().clone(), &destination_namespace);
+
+    let source_secret = source_secrets
         .get(&trade.spec.source)
         .await
         .map_err(|_| ReconcilerError::SecretNotFound)?;
 
-    let dest_secret = secrets
+    let dest_secret = dest_secrets
         .get(&trade.spec.destination)
         .await
         .map_err(|_| ReconcilerError::SecretNotFound)?;
 
-    match trade.spec.strategy {
+    let desired_values: HashMap<String, ByteString> = match trade.spec.strategy {
         // Simple copy of a field from source to dest
-        PatchStrategy::Copy { ref items } => {
-            // Compile a hashmap of all the source values we care about.
-            // If any are missing, raise an error
-            let source_values: HashMap<String, ByteString> = items
-                .iter()
-                .map(|item| {
-                    source_secret
-                        .data
-                        .as_ref()
-                        .and_then(|data| {
-                            data.get(&item.source)
-                                .map(|value| (item.source.clone(), value.clone()))
-                        })
-                        .ok_or(ReconcilerError::SourceFieldMissing)
-                })
-                .collect::<Result<HashMap<_, _>, ReconcilerError>>()?;
-
-            // Compile a hashmap of Options of all the dest values we care about.
-            let dest_values: HashMap<String, Option<ByteString>> = items
-                .iter()
-                .map(|item| {
-                    dest_secret
-                        .data
-                        .as_ref()
-                        .and_then(|data| {
-                            data.get(&item.destination)
-                                .map(|value| (item.destination.clone(), Some(value.clone())))
-                        })
-                        .unwrap_or_else(|| (item.destination.clone(), None))
-                })
-                .collect();
-
-            // Compile a hashmap of all the changes that need to be applied
-            let updates: HashMap<&str, &ByteString> = items
-                .iter()
-                .filter_map(|item| {
-                    let source_value = source_values
-                        .get(&item.source)
-                        .expect("source key should exist");
-
-                    let dest_value = dest_values
-                        .get(&item.destination)
-                        .expect("dest key should atleast be None")
-                        .as_ref();
-
-                    if dest_value.is_none() || *source_value != *dest_value.unwrap() {
-                        Some((item.destination.as_ref(), source_value))
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            // If there's no changes to apply, we're done
-            if updates.is_empty() {
-                eprintln!("Destination already matches source. No updates needed.");
-                return Ok(ctx.get_ref().requeue_action());
+        PatchStrategy::Copy { ref items } => items
+            .iter()
+            .map(|item| {
+                source_secret
+                    .data
+                    .as_ref()
+                    .and_then(|data| data.get(&item.source))
+                    .map(|value| (item.destination.clone(), value.clone()))
+                    .ok_or(ReconcilerError::SourceFieldMissing)
+            })
+            .collect::<Result<HashMap<_, _>, ReconcilerError>>()?,
+
+        // Render templates against the full set of source fields
+        PatchStrategy::Template { ref items } => {
+            let source_context = decode_template_context(source_secret.data.as_ref());
+            render_template_items(items, &source_context)?
+        }
+    };
+
+    // Compile a hashmap of all the changes that need to be applied, skipping
+    // any destination key that already has the desired value
+    let updates: HashMap<&str, &ByteString> = desired_values
+        .iter()
+        .filter_map(|(destination, value)| {
+            let dest_value = dest_secret
+                .data
+                .as_ref()
+                .and_then(|data| data.get(destination));
+
+            if dest_value != Some(value) {
+                Some((destination.as_ref(), value))
+            } else {
+                None
             }
+        })
+        .collect();
+
+    // Destination keys this TradeSecret owned as of its last reconcile. If
+    // `strategy.items` has since been edited to drop one of them, it won't
+    // show up in `desired_values` any more and needs to be explicitly
+    // reverted, not just dropped from the annotation.
+    let annotation_key = managed_by_annotation(&trade_name);
+    let previously_managed_keys: HashSet<String> = parse_managed_keys(
+        dest_secret
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(&annotation_key)),
+    )
+    .into_iter()
+    .collect();
+
+    let removed_keys: Vec<&str> = previously_managed_keys
+        .iter()
+        .map(String::as_str)
+        .filter(|key| !desired_values.contains_key(*key))
+        .collect();
+
+    // If there's no changes to apply and nothing to revert, we're done
+    if updates.is_empty() && removed_keys.is_empty() {
+        eprintln!("Destination already matches source. No updates needed.");
+        return Ok(ctx.get_ref().requeue_action());
+    }
 
-            // Create the patch from the changes
-            let patch = serde_json::to_vec(&serde_json::json!({ "data": updates }))
-                .map_err(|_| ReconcilerError::Unknown)?;
-
-            eprintln!(
-                "Updating secret '{}': {}",
-                trade.spec.destination,
-                std::str::from_utf8(&patch).map_err(|_| ReconcilerError::Unknown)?,
-            );
-
-            // Apply the patch in strategic merge mode
-            secrets
-                .patch(&trade.spec.destination, &Default::default(), patch)
-                .await
-                .map_err(|e| {
-                    eprintln!("Error {}: {:?}", e, e);
-                    ReconcilerError::Unknown
-                })?;
-        }
+    // Record which destination keys this TradeSecret owns, so Cleanup knows
+    // exactly what to revert. An empty set means there's nothing left to
+    // manage, so drop the annotation entirely instead of writing "".
+    let managed_keys = desired_values.keys().cloned().collect::<Vec<_>>().join(",");
+    let annotation_value = if managed_keys.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::Value::String(managed_keys)
     };
 
+    // Strategic merge patches remove a map entry when it's set to null
+    let mut data_patch = serde_json::Map::new();
+    for (destination, value) in &updates {
+        data_patch.insert(
+            (*destination).to_owned(),
+            serde_json::to_value(value).map_err(|_| ReconcilerError::Unknown)?,
+        );
+    }
+    for destination in &removed_keys {
+        data_patch.insert((*destination).to_owned(), serde_json::Value::Null);
+    }
+
+    // Create the patch from the changes
+    let patch = serde_json::to_vec(&serde_json::json!({
+        "data": data_patch,
+        "metadata": {
+            "annotations": {
+                annotation_key: annotation_value,
+            }
+        }
+    }))
+    .map_err(|_| ReconcilerError::Unknown)?;
+
+    eprintln!(
+        "Updating secret '{}': {}",
+        trade.spec.destination,
+        std::str::from_utf8(&patch).map_err(|_| ReconcilerError::Unknown)?,
+    );
+
+    // Apply the patch in strategic merge mode
+    dest_secrets
+        .patch(&trade.spec.destination, &Default::default(), patch)
+        .await
+        .map_err(|e| {
+            eprintln!("Error {}: {:?}", e, e);
+            ReconcilerError::Unknown
+        })?;
+    ctx.get_ref().metrics().secrets_patched_total.inc();
+
     Ok(ctx.get_ref().requeue_action())
 }
 
+/// Reverts whatever `apply` wrote into the destination secret.
+///
+/// Looks up the destination keys this TradeSecret recorded in its
+/// `managed-by` annotation and removes exactly those, then drops the
+/// annotation itself.
+async fn cleanup(trade: TradeSecret, ctx: Context<Ctx>) -> Result<ReconcilerAction, ReconcilerError> {
+    let namespace: &str = &trade
+        .metadata
+        .namespace
+        .as_ref()
+        .expect("TradeSecrets should always be namespaced");
+
+    let trade_name = trade
+        .metadata
+        .name
+        .clone()
+        .expect("TradeSecrets should always be named");
+
+    eprintln!("Cleaning up {}.{}", namespace, trade_name);
+
+    // The TradeSecret is going away; it should no longer map any Secret back
+    // to a reconcile regardless of whether the revert below succeeds.
+    ctx.get_ref().deindex_trade_secret(&trade);
+
+    let destination_namespace = ctx
+        .get_ref()
+        .resolve_namespace(namespace, &trade.spec.destination_namespace)?;
+    let dest_secrets: Api<Secret> =
+        Api::namespaced(ctx.get_ref().client().clone(), &destination_namespace);
+
+    let dest_secret = dest_secrets
+        .get(&trade.spec.destination)
+        .await
+        .map_err(|_| ReconcilerError::SecretNotFound)?;
+
+    let annotation_key = managed_by_annotation(&trade_name);
+
+    let managed_keys: Vec<String> = parse_managed_keys(
+        dest_secret
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(&annotation_key)),
+    );
+
+    if managed_keys.is_empty() {
+        eprintln!(
+            "No keys managed by '{}' on '{}'. Nothing to clean up.",
+            trade_name, trade.spec.destination
+        );
+        return Ok(ctx.get_ref().requeue_action());
+    }
+
+    // Strategic merge patches remove a map entry when it's set to null
+    let removals: HashMap<&str, Option<()>> = managed_keys
+        .iter()
+        .map(|key| (key.as_str(), None))
+        .collect();
+
+    let patch = serde_json::to_vec(&serde_json::json!({
+        "data": removals,
+        "metadata": {
+            "annotations": {
+                annotation_key: serde_json::Value::Null,
+            }
+        }
+    }))
+    .map_err(|_| ReconcilerError::Unknown)?;
+
+    eprintln!(
+        "Reverting secret '{}': {}",
+        trade.spec.destination,
+        std::str::from_utf8(&patch).map_err(|_| ReconcilerError::Unknown)?,
+    );
+
+    dest_secrets
+        .patch(&trade.spec.destination, &Default::default(), patch)
+        .await
+        .map_err(|e| {
+            eprintln!("Error {}: {:?}", e, e);
+            ReconcilerError::Unknown
+        })?;
+    ctx.get_ref().metrics().secrets_patched_total.inc();
+
+    Ok(ctx.get_ref().requeue_action())
+}
+
+/// Drives `apply`/`cleanup` through kube-runtime's finalizer helper so a
+/// TradeSecret's destination keys are reverted before it's allowed to delete.
+async fn reconcile(
+    trade: TradeSecret,
+    ctx: Context<Ctx>,
+) -> Result<ReconcilerAction, ReconcilerError> {
+    let _timer = ctx
+        .get_ref()
+        .metrics()
+        .reconcile_duration_seconds
+        .start_timer();
+
+    let namespace = trade
+        .metadata
+        .namespace
+        .clone()
+        .expect("TradeSecrets should always be namespaced");
+
+    ctx.get_ref().index_trade_secret(&trade);
+
+    let trades: Api<TradeSecret> = Api::namespaced(ctx.get_ref().client().clone(), &namespace);
+
+    let result = finalizer(&trades, TRADE_SECRET_FINALIZER, trade, |event| {
+        let ctx = ctx.clone();
+        async move {
+            match event {
+                FinalizerEvent::Apply(trade) => apply(trade, ctx).await,
+                FinalizerEvent::Cleanup(trade) => cleanup(trade, ctx).await,
+            }
+        }
+    })
+    .await
+    .map_err(|e| match e {
+        FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => e,
+        e => ReconcilerError::FinalizerFailed(e.to_string()),
+    });
+
+    if result.is_ok() {
+        ctx.get_ref().metrics().reconcile_success_total.inc();
+    }
+
+    result
+}
+
 /// The controller error handler.
 ///
-/// Don't do anything.  Just requeue the trade secrets.
-fn error_policy(_error: &ReconcilerError, ctx: Context<Ctx>) -> ReconcilerAction {
+/// Don't do anything except record the failure and requeue the trade secret.
+fn error_policy(error: &ReconcilerError, ctx: Context<Ctx>) -> ReconcilerAction {
+    ctx.get_ref()
+        .metrics()
+        .reconcile_error_total
+        .with_label_values(&[error.metric_label()])
+        .inc();
+
     ctx.get_ref().requeue_action()
 }
 
 /// Controller creation
-async fn controller(client: Client, requeue_time: Duration) -> Result<()> {
+async fn controller(
+    client: Client,
+    requeue_time: Duration,
+    metrics: Metrics,
+    readiness: Readiness,
+    allow_cross_namespace: bool,
+) -> Result<()> {
     let trades = Api::<TradeSecret>::all(client.clone());
-    let context = Context::new(Ctx::new(client.clone(), requeue_time));
-
-    Controller::new(trades, ListParams::default())
+    let secrets = Api::<Secret>::all(client.clone());
+    let secret_index: SecretIndex = Arc::new(Mutex::new(HashMap::new()));
+    let context = Context::new(Ctx::new(
+        client.clone(),
+        requeue_time,
+        secret_index.clone(),
+        metrics,
+        allow_cross_namespace,
+    ));
+
+    let controller = Controller::new(trades, ListParams::default());
+
+    // Only report ready once the Controller's own reflector has completed
+    // its initial list and its store is actually populated, rather than a
+    // separate, throwaway list() call that races the real watch below.
+    let store = controller.store();
+    tokio::spawn(async move {
+        store.wait_until_ready().await.expect("reflector init stream closed");
+        readiness.set_ready();
+    });
+
+    controller
+        // Requeue a TradeSecret as soon as a Secret it reads from or writes
+        // to changes, instead of waiting for the next timed requeue
+        .watches(secrets, ListParams::default(), move |secret| {
+            let namespace = secret.metadata.namespace.clone().unwrap_or_default();
+            let name = secret.metadata.name.clone().unwrap_or_default();
+
+            secret_index
+                .lock()
+                .expect("secret index lock poisoned")
+                .get(&(namespace, name))
+                .cloned()
+                .unwrap_or_default()
+        })
         .run(reconcile, error_policy, context)
         // TODO: This is from the example.  This should be done differently.
         .for_each(|res| async move {
@@ -198,9 +668,23 @@ async fn controller(client: Client, requeue_time: Duration) -> Result<()> {
 }
 
 /// Run the controller
-pub(crate) async fn run_controller(client: kube::Client, requeue_time: Duration) -> Result<()> {
+pub(crate) async fn run_controller(
+    client: kube::Client,
+    requeue_time: Duration,
+    metrics_addr: SocketAddr,
+    allow_cross_namespace: bool,
+) -> Result<()> {
     check_crd_status(client.clone()).await?;
-    controller(client.clone(), requeue_time).await
+
+    let metrics = Metrics::new()?;
+    let readiness = Readiness::new();
+
+    // Either the controller or the metrics server exiting (e.g. on error)
+    // should bring the whole process down.
+    tokio::select! {
+        res = controller(client, requeue_time, metrics.clone(), readiness.clone(), allow_cross_namespace) => res,
+        res = crate::metrics::run_server(metrics_addr, metrics, readiness) => res,
+    }
 }
 
 /// controller related commands
@@ -210,6 +694,14 @@ pub(crate) enum ControllerCommand {
     Run {
         #[structopt(long, default_value = "5m", env = "TS_REQUEUE_TIME")]
         requeue_time: Duration,
+
+        #[structopt(long, default_value = "0.0.0.0:8080", env = "TS_METRICS_ADDR")]
+        metrics_addr: SocketAddr,
+
+        /// Allow a TradeSecret's source/destination to live in a different
+        /// namespace than the TradeSecret itself.
+        #[structopt(long, env = "TS_ALLOW_CROSS_NAMESPACE")]
+        allow_cross_namespace: bool,
     },
 }
 
@@ -217,6 +709,142 @@ pub(crate) async fn run_command(command: ControllerCommand) -> Result<()> {
     let client = kube::Client::try_default().await?;
 
     match command {
-        ControllerCommand::Run { requeue_time, .. } => run_controller(client, requeue_time).await,
+        ControllerCommand::Run {
+            requeue_time,
+            metrics_addr,
+            allow_cross_namespace,
+            ..
+        } => run_controller(client, requeue_time, metrics_addr, allow_cross_namespace).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crd::{PatchCopyItem, TradeSecretSpec};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn trade_secret(name: &str, namespace: &str, source: &str, destination: &str) -> TradeSecret {
+        TradeSecret {
+            metadata: ObjectMeta {
+                name: Some(name.to_owned()),
+                namespace: Some(namespace.to_owned()),
+                ..Default::default()
+            },
+            spec: TradeSecretSpec {
+                source: source.to_owned(),
+                destination: destination.to_owned(),
+                source_namespace: None,
+                destination_namespace: None,
+                strategy: PatchStrategy::Copy {
+                    items: vec![PatchCopyItem {
+                        source: "a".to_owned(),
+                        destination: "b".to_owned(),
+                    }],
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn effective_namespace_defaults_to_trade_namespace() {
+        assert_eq!(effective_namespace("ns", &None), "ns");
+    }
+
+    #[test]
+    fn effective_namespace_prefers_explicit() {
+        assert_eq!(
+            effective_namespace("ns", &Some("other".to_owned())),
+            "other"
+        );
+    }
+
+    #[test]
+    fn parse_managed_keys_handles_empty_and_missing() {
+        assert!(parse_managed_keys(None).is_empty());
+        assert!(parse_managed_keys(Some(&String::new())).is_empty());
+        assert_eq!(
+            parse_managed_keys(Some(&"a,b".to_owned())),
+            vec!["a".to_owned(), "b".to_owned()]
+        );
+    }
+
+    #[test]
+    fn prune_stale_refs_drops_entries_not_kept() {
+        let trade = trade_secret("t", "ns", "src", "dst");
+        let trade_ref = ObjectRef::from_obj(&trade);
+
+        let mut index = HashMap::new();
+        index.insert(("ns".to_owned(), "old-dst".to_owned()), {
+            let mut set = HashSet::new();
+            set.insert(trade_ref.clone());
+            set
+        });
+
+        prune_stale_refs(&mut index, &trade_ref, &[("ns".to_owned(), "src".to_owned())]);
+
+        assert!(!index.contains_key(&("ns".to_owned(), "old-dst".to_owned())));
+    }
+
+    #[test]
+    fn remove_all_refs_clears_every_entry() {
+        let trade = trade_secret("t", "ns", "src", "dst");
+        let trade_ref = ObjectRef::from_obj(&trade);
+
+        let mut index = HashMap::new();
+        for key in [
+            ("ns".to_owned(), "src".to_owned()),
+            ("ns".to_owned(), "dst".to_owned()),
+        ] {
+            let mut set = HashSet::new();
+            set.insert(trade_ref.clone());
+            index.insert(key, set);
+        }
+
+        remove_all_refs(&mut index, &trade_ref);
+
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn decode_template_context_skips_non_utf8() {
+        let mut data = std::collections::BTreeMap::new();
+        data.insert("text".to_owned(), ByteString(b"hello".to_vec()));
+        data.insert("binary".to_owned(), ByteString(vec![0xff, 0xfe]));
+
+        let context = decode_template_context(Some(&data));
+
+        assert_eq!(context.get("text"), Some(&"hello".to_owned()));
+        assert!(!context.contains_key("binary"));
+    }
+
+    #[test]
+    fn render_template_items_renders_referenced_keys() {
+        let mut context = HashMap::new();
+        context.insert("username".to_owned(), "alice".to_owned());
+
+        let items = vec![PatchTemplateItem {
+            destination: "greeting".to_owned(),
+            template: "hello {{source.username}}".to_owned(),
+        }];
+
+        let rendered = render_template_items(&items, &context).expect("should render");
+
+        assert_eq!(
+            rendered.get("greeting").map(|v| v.0.clone()),
+            Some(b"hello alice".to_vec())
+        );
+    }
+
+    #[test]
+    fn render_template_items_fails_on_missing_key() {
+        let context = HashMap::new();
+
+        let items = vec![PatchTemplateItem {
+            destination: "greeting".to_owned(),
+            template: "hello {{source.username}}".to_owned(),
+        }];
+
+        assert!(render_template_items(&items, &context).is_err());
     }
 }
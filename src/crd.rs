@@ -1,8 +1,9 @@
 use anyhow::{anyhow, Context, Result};
 use apiexts::CustomResourceDefinition;
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1beta1 as apiexts;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1 as apiexts;
 use kube::api::Api;
 use kube_derive::CustomResource;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
@@ -10,29 +11,55 @@ const CRD_NAME: &str = "tradesecrets.secrets.ohnozombi.es";
 const CRD_VERSION: &str = "v1alpha1";
 
 /// An individual copy operation
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub(crate) struct PatchCopyItem {
     pub source: String,
     pub destination: String,
 }
 
+/// An individual templated destination field
+///
+/// `template` is rendered with a Handlebars context exposing every key of
+/// the source secret under `source` (e.g. `{{source.username}}`).
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct PatchTemplateItem {
+    pub destination: String,
+    pub template: String,
+}
+
 /// A patch strategy, mostly for future expansion
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(tag = "type")]
 pub(crate) enum PatchStrategy {
     #[serde(rename = "copy")]
     Copy { items: Vec<PatchCopyItem> },
+
+    #[serde(rename = "template")]
+    Template { items: Vec<PatchTemplateItem> },
 }
 
 /// The TradeSecret type
-#[derive(CustomResource, Debug, Clone, Deserialize, Serialize)]
+#[derive(CustomResource, Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[kube(group = "secrets.ohnozombi.es", version = "v1alpha1", namespaced)]
 #[kube(shortname = "trades")]
-// Use v1beta1 so it doesn't require a schema, which isn't supported by kube
-#[kube(apiextensions = "v1beta1")]
+#[kube(apiextensions = "v1")]
 pub(crate) struct TradeSecretSpec {
     pub source: String,
     pub destination: String,
+
+    /// The namespace the source secret lives in. Defaults to the
+    /// TradeSecret's own namespace.
+    #[serde(rename = "sourceNamespace", skip_serializing_if = "Option::is_none")]
+    pub source_namespace: Option<String>,
+
+    /// The namespace the destination secret lives in. Defaults to the
+    /// TradeSecret's own namespace.
+    #[serde(
+        rename = "destinationNamespace",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub destination_namespace: Option<String>,
+
     pub strategy: PatchStrategy,
 }
 
@@ -44,10 +71,7 @@ pub(crate) async fn check_crd_status(client: kube::Client) -> Result<()> {
         .await
         .with_context(|| format!("Could not find the crd: {}", CRD_NAME))?;
 
-    let versions = ts_crd
-        .spec
-        .versions
-        .ok_or_else(|| anyhow!("The CRD is missing the version field."))?;
+    let versions = ts_crd.spec.versions;
 
     if versions.len() != 1 {
         return Err(anyhow!("Only expected one version in the CRD."));
@@ -8,6 +8,7 @@ use structopt::StructOpt;
 mod controller;
 mod crd;
 mod duration;
+mod metrics;
 
 #[derive(StructOpt, Debug, Clone)]
 enum Command {
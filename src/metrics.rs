@@ -0,0 +1,132 @@
+//! Health/readiness HTTP endpoints and Prometheus reconcile metrics.
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use anyhow::Result;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+/// Whether the controller is ready to serve traffic.
+///
+/// Flips to `true` once the CRD has been confirmed installed and the
+/// TradeSecret reflector has completed its initial list.
+#[derive(Clone)]
+pub(crate) struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub(crate) fn set_ready(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Reconcile-related Prometheus metrics.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub(crate) reconcile_success_total: IntCounter,
+    pub(crate) reconcile_error_total: IntCounterVec,
+    pub(crate) secrets_patched_total: IntCounter,
+    pub(crate) reconcile_duration_seconds: Histogram,
+    pub(crate) watched_trade_secrets: IntGauge,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let reconcile_success_total = IntCounter::new(
+            "tradesecrets_reconcile_success_total",
+            "Number of TradeSecret reconciles that completed successfully",
+        )?;
+        let reconcile_error_total = IntCounterVec::new(
+            Opts::new(
+                "tradesecrets_reconcile_error_total",
+                "Number of TradeSecret reconciles that failed, by error",
+            ),
+            &["reason"],
+        )?;
+        let secrets_patched_total = IntCounter::new(
+            "tradesecrets_secrets_patched_total",
+            "Number of destination Secrets actually patched",
+        )?;
+        let reconcile_duration_seconds = Histogram::with_opts(HistogramOpts::new(
+            "tradesecrets_reconcile_duration_seconds",
+            "Time spent in a single TradeSecret reconcile",
+        ))?;
+        let watched_trade_secrets = IntGauge::new(
+            "tradesecrets_watched_trade_secrets",
+            "Number of TradeSecrets currently tracked by the controller",
+        )?;
+
+        registry.register(Box::new(reconcile_success_total.clone()))?;
+        registry.register(Box::new(reconcile_error_total.clone()))?;
+        registry.register(Box::new(secrets_patched_total.clone()))?;
+        registry.register(Box::new(reconcile_duration_seconds.clone()))?;
+        registry.register(Box::new(watched_trade_secrets.clone()))?;
+
+        Ok(Self {
+            registry,
+            reconcile_success_total,
+            reconcile_error_total,
+            secrets_patched_total,
+            reconcile_duration_seconds,
+            watched_trade_secrets,
+        })
+    }
+
+    fn gather(&self) -> Vec<u8> {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("failed to encode metrics");
+        buffer
+    }
+}
+
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+async fn readyz(readiness: web::Data<Readiness>) -> HttpResponse {
+    if readiness.is_ready() {
+        HttpResponse::Ok().body("ok")
+    } else {
+        HttpResponse::ServiceUnavailable().body("not ready")
+    }
+}
+
+async fn metrics_handler(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.gather())
+}
+
+/// Runs the `/healthz`, `/readyz` and `/metrics` endpoints until the process exits.
+pub(crate) async fn run_server(addr: SocketAddr, metrics: Metrics, readiness: Readiness) -> Result<()> {
+    HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(readiness.clone()))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+            .route("/metrics", web::get().to(metrics_handler))
+    })
+    .bind(addr)?
+    .run()
+    .await?;
+
+    Ok(())
+}